@@ -11,8 +11,11 @@
 //! A pass that promotes borrows of constant rvalues.
 //!
 //! The rvalues considered constant are trees of temps,
-//! each with exactly one initialization, and holding
-//! a constant value with no interior mutability.
+//! holding a constant value with no interior mutability,
+//! each initialized either once, or more than once as long
+//! as every initialization sits on its own branch of the
+//! CFG mutually exclusive with all the others (e.g. the
+//! arms of an `if` or `match`) and merges before any use.
 //! They are placed into a new MIR constant body in
 //! `promoted` and the borrow rvalue is replaced with
 //! a `Literal::Promoted` using the index into `promoted`
@@ -28,20 +31,25 @@ use rustc::ty::{self, TyCtxt};
 use syntax::codemap::Span;
 
 use build::Location;
-use traversal::ReversePostorder;
+use traversal::{self, ReversePostorder};
 
+use std::collections::HashMap;
 use std::mem;
 
 /// State of a temporary during collection and promotion.
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub enum TempState {
     /// No references to this temp.
     Undefined,
-    /// One direct assignment and any number of direct uses.
-    /// A borrow of this temp is promotable if the assigned
-    /// value is qualified as constant.
+    /// One or more direct assignments and any number of direct uses.
+    /// A borrow of this temp is promotable if the assigned value(s)
+    /// are qualified as constant. More than one `location` is only
+    /// allowed when every initialization sits on a branch of the CFG
+    /// that is mutually exclusive with every other one (e.g. the arms
+    /// of an `if` or `match`), and every use is dominated by whichever
+    /// of them actually ran.
     Defined {
-        location: Location,
+        locations: Vec<Location>,
         uses: usize
     },
     /// Any other combination of assignments/uses.
@@ -61,6 +69,161 @@ impl TempState {
     }
 }
 
+/// A dominator tree over the basic blocks of a `Mir`, built with the
+/// standard Cooper-Harvey-Kennedy iterative algorithm. Besides the
+/// immediate dominators it keeps each block's reverse-postorder index
+/// and successors, which is enough to answer dominance, mutual
+/// exclusivity, nearest common dominators and whether a branch's arms
+/// are exactly a given set of blocks.
+struct Dominators {
+    idom: HashMap<BasicBlock, BasicBlock>,
+    rpo_index: HashMap<BasicBlock, usize>,
+    succs: HashMap<BasicBlock, Vec<BasicBlock>>,
+}
+
+impl Dominators {
+    fn new(mir: &Mir) -> Dominators {
+        let rpo: Vec<_> = traversal::reverse_postorder(mir).map(|(bb, _)| bb).collect();
+        let rpo_index: HashMap<_, _> = rpo.iter().cloned().enumerate()
+            .map(|(i, bb)| (bb, i)).collect();
+
+        let mut preds: HashMap<BasicBlock, Vec<BasicBlock>> = HashMap::new();
+        let mut succs: HashMap<BasicBlock, Vec<BasicBlock>> = HashMap::new();
+        for &bb in &rpo {
+            let bb_succs: Vec<_> = mir[bb].terminator().successors().iter().cloned().collect();
+            for &succ in &bb_succs {
+                preds.entry(succ).or_insert_with(Vec::new).push(bb);
+            }
+            succs.insert(bb, bb_succs);
+        }
+
+        let mut idom = HashMap::new();
+        idom.insert(rpo[0], rpo[0]);
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &bb in rpo.iter().skip(1) {
+                let ps = match preds.get(&bb) {
+                    Some(ps) => ps,
+                    None => continue
+                };
+                let mut new_idom = None;
+                for &p in ps {
+                    if !idom.contains_key(&p) {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => p,
+                        Some(cur) => Self::intersect(&idom, &rpo_index, cur, p)
+                    });
+                }
+                if let Some(new_idom) = new_idom {
+                    if idom.get(&bb) != Some(&new_idom) {
+                        idom.insert(bb, new_idom);
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        Dominators { idom: idom, rpo_index: rpo_index, succs: succs }
+    }
+
+    fn intersect(idom: &HashMap<BasicBlock, BasicBlock>,
+                 rpo_index: &HashMap<BasicBlock, usize>,
+                 mut a: BasicBlock,
+                 mut b: BasicBlock)
+                 -> BasicBlock {
+        while a != b {
+            while rpo_index[&a] > rpo_index[&b] {
+                a = idom[&a];
+            }
+            while rpo_index[&b] > rpo_index[&a] {
+                b = idom[&b];
+            }
+        }
+        a
+    }
+
+    fn block_dominates(&self, a: BasicBlock, b: BasicBlock) -> bool {
+        let mut cur = b;
+        loop {
+            if cur == a {
+                return true;
+            }
+            let next = self.idom[&cur];
+            if next == cur {
+                return cur == a;
+            }
+            cur = next;
+        }
+    }
+
+    /// Two distinct blocks count as mutually exclusive here when neither
+    /// dominates the other. That alone does not mean control flow can
+    /// only reach one of them: an arm of a branch and a block after the
+    /// arms merge also pass. `dominates_one_of` is what additionally
+    /// requires the initializing blocks to be exactly the arms of one
+    /// branch before any use of the temp is counted.
+    fn mutually_exclusive(&self, a: BasicBlock, b: BasicBlock) -> bool {
+        a != b && !self.block_dominates(a, b) && !self.block_dominates(b, a)
+    }
+
+    fn dominates(&self, def: &Location, use_: &Location) -> bool {
+        if def.block == use_.block {
+            def.statement_index <= use_.statement_index
+        } else {
+            self.block_dominates(def.block, use_.block)
+        }
+    }
+
+    fn nearest_common_dominator(&self, blocks: &[BasicBlock]) -> BasicBlock {
+        let mut cur = blocks[0];
+        for &bb in &blocks[1..] {
+            cur = Self::intersect(&self.idom, &self.rpo_index, cur, bb);
+        }
+        cur
+    }
+
+    /// Whether `branch`'s terminator has exactly the blocks in
+    /// `locations` as its successors (in any order), i.e. every arm of
+    /// the branch is accounted for by an initialization and there's no
+    /// arm left over (e.g. one that diverges instead of assigning).
+    /// `Promoter::promote_temp_multi` can only clone a branch it knows
+    /// every arm of, so this must hold before a multi-location temp is
+    /// accepted as promotable.
+    fn exhaustively_covers(&self, branch: BasicBlock, locations: &[BasicBlock]) -> bool {
+        let succs = match self.succs.get(&branch) {
+            Some(succs) => succs,
+            None => return false
+        };
+        succs.len() == locations.len() &&
+            succs.iter().all(|succ| locations.contains(succ))
+    }
+
+    /// Whether `use_` is necessarily preceded by one of `locations`,
+    /// i.e. it's safe to treat `use_` as reading a value that one of
+    /// them (we don't know which, until runtime) has just initialized.
+    ///
+    /// For a single initialization this is plain dominance. For more
+    /// than one, no individual `Location` in `locations` dominates a
+    /// use past their merge point (that's the entire reason they're
+    /// mutually exclusive), so what must dominate `use_` is their
+    /// *nearest common dominator* — the branch they all come from —
+    /// and that branch's arms must be exactly `locations`, or some arm
+    /// could reach `use_` without having initialized anything.
+    fn dominates_one_of(&self, locations: &[Location], use_: &Location) -> bool {
+        if locations.len() == 1 {
+            return self.dominates(&locations[0], use_);
+        }
+        let blocks: Vec<_> = locations.iter().map(|loc| loc.block).collect();
+        let branch = self.nearest_common_dominator(&blocks);
+        self.exhaustively_covers(branch, &blocks) &&
+            (branch == use_.block || self.block_dominates(branch, use_.block))
+    }
+}
+
 /// A "root candidate" for promotion, which will become the
 /// returned value in a promoted MIR, unless it's a subset
 /// of a larger candidate.
@@ -68,15 +231,74 @@ pub enum Candidate {
     /// Borrow of a constant temporary.
     Ref(Location),
 
-    /// Array of indices found in the third argument of
-    /// a call to one of the simd_shuffleN intrinsics.
-    ShuffleIndices(BasicBlock)
+    /// An argument of a call to an intrinsic that must be promoted to a
+    /// constant, e.g. the shuffle-index argument of a `simd_shuffleN`
+    /// call. `arg_index` should be looked up via
+    /// `intrinsic_promoted_args` wherever candidates get collected,
+    /// rather than hardcoded per-intrinsic.
+    IntrinsicArg {
+        block: BasicBlock,
+        arg_index: usize
+    }
+}
+
+/// Maps the name of an intrinsic to the indices of the arguments in a
+/// call to it that must be promoted to constants before codegen. Adding
+/// support for a new const-argument intrinsic only requires a table
+/// entry here, rather than touching the promotion machinery itself.
+///
+/// Candidate collection (walking the MIR to find calls to intrinsics and
+/// pushing `Candidate::IntrinsicArg` for the indices this table names)
+/// belongs to whatever pass builds the `Vec<Candidate>` passed into
+/// `promote_candidates` -- `qualify_consts` in a full tree. That pass
+/// isn't part of this crate snapshot, so nothing here calls this
+/// function yet; it's dead code until that call site is added.
+pub fn intrinsic_promoted_args(name: &str) -> &'static [usize] {
+    if name.starts_with("simd_shuffle") {
+        return &[2];
+    }
+    &[]
+}
+
+/// Why a candidate's root temp didn't qualify for promotion, recorded when
+/// collection/promotion runs in diagnostic mode (see
+/// `collect_temps_with_diagnostics`). This lets the borrow checker surface
+/// an actionable note (e.g. "this borrow could be promoted to `'static` if
+/// the value did not contain a `Cell`") instead of a bare downstream
+/// lifetime error.
+///
+/// `TempCollector` only ever produces `MultipleAssignments` itself, since
+/// it has no type information to work with; `InteriorMutability`,
+/// `NonConstInitializer` and `DropGlue` are meant to be recorded by the
+/// const-qualification pass that decides whether a temp's assigned value
+/// actually qualifies as constant, using the same `Span` keying.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PromotionFailure {
+    /// The value (or one of its fields) has interior mutability, e.g. it
+    /// contains a `Cell`.
+    InteriorMutability,
+    /// The initializer isn't a constant expression.
+    NonConstInitializer,
+    /// The value's type has drop glue, so it can't be promoted to
+    /// `'static` even though it's otherwise constant.
+    DropGlue,
+    /// The temp has more than one initialization not on mutually
+    /// exclusive branches of the CFG, or is used somewhere not
+    /// dominated by the initializations that can reach it (for a
+    /// single initialization, that's ordinary dominance; for several
+    /// mutually exclusive ones, it's dominance by their shared branch
+    /// point, and that branch must have no arm left uninitialized).
+    MultipleAssignments
 }
 
 struct TempCollector {
     temps: Vec<TempState>,
     location: Location,
-    span: Span
+    span: Span,
+    dominators: Dominators,
+    /// `Some` only when collection is run via
+    /// `collect_temps_with_diagnostics`.
+    failures: Option<Vec<(Span, PromotionFailure)>>
 }
 
 impl<'tcx> Visitor<'tcx> for TempCollector {
@@ -89,31 +311,75 @@ impl<'tcx> Visitor<'tcx> for TempCollector {
                 return;
             }
 
+            let dominators = &self.dominators;
+            let location = self.location;
+            let span = self.span;
             let temp = &mut self.temps[index as usize];
+            let was_defined = if let TempState::Defined { .. } = *temp { true } else { false };
             if *temp == TempState::Undefined {
                 match context {
                     LvalueContext::Store |
                     LvalueContext::Call => {
                         *temp = TempState::Defined {
-                            location: self.location,
+                            locations: vec![location],
                             uses: 0
                         };
                         return;
                     }
                     _ => { /* mark as unpromotable below */ }
                 }
-            } else if let TempState::Defined { ref mut uses, .. } = *temp {
+            } else if let TempState::Defined { ref mut locations, ref mut uses } = *temp {
                 match context {
+                    LvalueContext::Store |
+                    LvalueContext::Call => {
+                        // A second initialization is only promotable if it's
+                        // on a branch mutually exclusive with every one seen
+                        // so far, e.g. the two arms of an `if` or `match`.
+                        let disjoint = locations.iter().all(|prev| {
+                            dominators.mutually_exclusive(prev.block, location.block)
+                        });
+                        if disjoint {
+                            locations.push(location);
+                            return;
+                        }
+                    }
                     LvalueContext::Borrow {..} |
                     LvalueContext::Consume |
                     LvalueContext::Inspect => {
-                        *uses += 1;
-                        return;
+                        // A use is only sound if whichever initialization
+                        // ran is guaranteed to have run before this point.
+                        // Past the point where disjoint initializations
+                        // merge back together, that's not dominance by
+                        // any one `Location` (none of them individually
+                        // dominates the merge point, that's the point of
+                        // them being mutually exclusive) but by their
+                        // common origin, the branch they're all arms of.
+                        let dominated = dominators.dominates_one_of(locations, &location);
+                        if dominated {
+                            *uses += 1;
+                            return;
+                        }
                     }
                     _ => { /* mark as unpromotable below */ }
                 }
             }
             *temp = TempState::Unpromotable;
+            // Only the store and direct-use checks above can fail for
+            // reasons `MultipleAssignments` describes; other contexts
+            // (e.g. a projection) are not recorded.
+            let assignment_or_use = match context {
+                LvalueContext::Store |
+                LvalueContext::Call |
+                LvalueContext::Borrow {..} |
+                LvalueContext::Consume |
+                LvalueContext::Inspect => true,
+                _ => false
+            };
+            if was_defined && assignment_or_use {
+                if let Some(ref mut failures) = self.failures {
+                    failures.push((span, PromotionFailure::MultipleAssignments));
+                }
+            }
         }
     }
 
@@ -137,24 +403,43 @@ impl<'tcx> Visitor<'tcx> for TempCollector {
 }
 
 pub fn collect_temps(mir: &Mir, rpo: &mut ReversePostorder) -> Vec<TempState> {
+    collect_temps_inner(mir, rpo, false).0
+}
+
+/// Like `collect_temps`, but also returns, for every already-assigned temp
+/// that ended up `Unpromotable` because a further assignment or a direct
+/// use failed the checks, the span and reason it was rejected. A temp used
+/// before any assignment is not recorded. Everything else about collection is
+/// unchanged; this is purely an opt-in side-channel for diagnostics.
+pub fn collect_temps_with_diagnostics(mir: &Mir, rpo: &mut ReversePostorder)
+                                       -> (Vec<TempState>, Vec<(Span, PromotionFailure)>) {
+    let (temps, failures) = collect_temps_inner(mir, rpo, true);
+    (temps, failures.unwrap_or_default())
+}
+
+fn collect_temps_inner(mir: &Mir, rpo: &mut ReversePostorder, record_failures: bool)
+                        -> (Vec<TempState>, Option<Vec<(Span, PromotionFailure)>>) {
     let mut collector = TempCollector {
         temps: vec![TempState::Undefined; mir.temp_decls.len()],
         location: Location {
             block: START_BLOCK,
             statement_index: 0
         },
-        span: mir.span
+        span: mir.span,
+        dominators: Dominators::new(mir),
+        failures: if record_failures { Some(vec![]) } else { None }
     };
     for (bb, data) in rpo {
         collector.visit_basic_block_data(bb, data);
     }
-    collector.temps
+    (collector.temps, collector.failures)
 }
 
 struct Promoter<'a, 'tcx: 'a> {
     source: &'a mut Mir<'tcx>,
     promoted: Mir<'tcx>,
     temps: &'a mut Vec<TempState>,
+    dominators: &'a Dominators,
 
     /// If true, all nested temps are also kept in the
     /// source MIR, not moved to the promoted MIR.
@@ -190,14 +475,17 @@ impl<'a, 'tcx> Promoter<'a, 'tcx> {
     fn promote_temp(&mut self, index: u32) -> u32 {
         let index = index as usize;
         let old_keep_original = self.keep_original;
-        let (bb, stmt_idx) = match self.temps[index] {
-            TempState::Defined {
-                location: Location { block, statement_index },
-                uses
-            } if uses > 0 => {
+        let (bb, stmt_idx) = match self.temps[index].clone() {
+            TempState::Defined { locations, uses } if uses > 0 => {
                 if uses > 1 {
                     self.keep_original = true;
                 }
+                if locations.len() > 1 {
+                    let new_index = self.promote_temp_multi(index, &locations, uses);
+                    self.keep_original = old_keep_original;
+                    return new_index;
+                }
+                let Location { block, statement_index } = locations[0];
                 (block, statement_index)
             }
             temp =>  {
@@ -288,6 +576,99 @@ impl<'a, 'tcx> Promoter<'a, 'tcx> {
         new_index
     }
 
+    /// Like `promote_temp`, but for a temp initialized along more than one
+    /// mutually exclusive branch (see `TempState::Defined`). Rather than
+    /// lifting a single assignment, this clones the branching terminator
+    /// that separates the initializations into the promoted MIR, so the
+    /// promoted body recomputes whichever one of them the source function
+    /// would have run.
+    ///
+    /// `TempCollector` (via `Dominators::dominates_one_of`) only ever
+    /// lets a multi-location temp have `uses > 0` when every location is
+    /// a direct successor of their nearest common dominator *and*
+    /// accounts for every one of that dominator's arms, so those are
+    /// treated as invariants here rather than re-checked gracefully:
+    /// anything more nested (e.g. further branching before the merge,
+    /// or an arm that diverges instead of initializing) never reaches
+    /// this function with `uses > 0` in the first place.
+    fn promote_temp_multi(&mut self, index: usize, locations: &[Location], uses: usize) -> u32 {
+        let old_keep_original = self.keep_original;
+        // The arms still run in the source function regardless of which
+        // borrow got promoted, so their assignments (and anything they
+        // in turn reference) must stay behind while we recurse into them.
+        self.keep_original = true;
+
+        let blocks: Vec<_> = locations.iter().map(|loc| loc.block).collect();
+        let branch = self.dominators.nearest_common_dominator(&blocks);
+
+        let new_index = self.promoted.temp_decls.len() as u32;
+        let new_temp = Lvalue::Temp(new_index);
+        self.promoted.temp_decls.push(TempDecl {
+            ty: self.source.temp_decls[index].ty
+        });
+
+        // Jump from whatever block was current into the cloned branch,
+        // the same way the `Call` path above wires `last` into `new_target`.
+        let last = self.promoted.basic_blocks.len() - 1;
+        let branch_block = self.new_block();
+        self.promoted.basic_blocks[last].terminator_mut().kind = TerminatorKind::Goto {
+            target: branch_block
+        };
+
+        let mut branch_terminator = self.source[branch].terminator().clone();
+
+        let mut arms = vec![];
+        for target in branch_terminator.kind.successors_mut() {
+            let loc = *locations.iter().find(|loc| loc.block == *target)
+                .unwrap_or_else(|| {
+                    span_bug!(branch_terminator.span,
+                              "tmp{} not initialized on every arm of its \
+                               defining branch", index)
+                });
+
+            let no_stmts = self.source[loc.block].statements.len();
+            if loc.statement_index >= no_stmts {
+                span_bug!(branch_terminator.span,
+                          "tmp{} initialized by a terminator is not \
+                           supported for disjoint-branch promotion", index);
+            }
+
+            let arm = self.new_block();
+            let statement = &self.source[loc.block].statements[loc.statement_index];
+            let StatementKind::Assign(_, ref rhs) = statement.kind;
+            let mut rvalue = rhs.clone();
+            self.visit_rvalue(&mut rvalue);
+            self.assign(new_temp.clone(), rvalue, statement.span);
+
+            *target = arm;
+            arms.push(arm);
+        }
+
+        let merge = self.new_block();
+        for arm in arms {
+            self.promoted.basic_blocks[arm.index()].terminator_mut().kind =
+                TerminatorKind::Goto { target: merge };
+        }
+
+        self.promoted.basic_blocks[branch_block.index()].terminator =
+            Some(branch_terminator);
+
+        // Restore the old duplication state.
+        self.keep_original = old_keep_original;
+
+        // Just like `promote_temp`: only retire this temp if it has a
+        // single use overall (and no enclosing temp is being kept in the
+        // source), so a second reference to it within the same (or a
+        // later) promoted expression still finds `Defined` and gets its
+        // own fresh copy, instead of tripping the "not promotable"
+        // span_bug above.
+        if uses <= 1 && !old_keep_original {
+            self.temps[index] = TempState::PromotedOut;
+        }
+
+        new_index
+    }
+
     fn promote_candidate(mut self, candidate: Candidate) {
         let span = self.promoted.span;
         let new_operand = Operand::Constant(Constant {
@@ -305,10 +686,10 @@ impl<'a, 'tcx> Promoter<'a, 'tcx> {
                     }
                 }
             }
-            Candidate::ShuffleIndices(bb) => {
-                match self.source[bb].terminator_mut().kind {
+            Candidate::IntrinsicArg { block, arg_index } => {
+                match self.source[block].terminator_mut().kind {
                     TerminatorKind::Call { ref mut args, .. } => {
-                        Rvalue::Use(mem::replace(&mut args[2], new_operand))
+                        Rvalue::Use(mem::replace(&mut args[arg_index], new_operand))
                     }
                     _ => bug!()
                 }
@@ -348,21 +729,22 @@ pub fn promote_candidates<'a, 'tcx>(mir: &mut Mir<'tcx>,
                 }
                 (statement.span, mir.lvalue_ty(tcx, dest).to_ty(tcx))
             }
-            Candidate::ShuffleIndices(bb) => {
-                let terminator = mir[bb].terminator();
+            Candidate::IntrinsicArg { block, arg_index } => {
+                let terminator = mir[block].terminator();
                 let ty = match terminator.kind {
                     TerminatorKind::Call { ref args, .. } => {
-                        mir.operand_ty(tcx, &args[2])
+                        mir.operand_ty(tcx, &args[arg_index])
                     }
                     _ => {
                         span_bug!(terminator.span,
-                                  "expected simd_shuffleN call to promote");
+                                  "expected intrinsic call to promote");
                     }
                 };
                 (terminator.span, ty)
             }
         };
 
+        let dominators = Dominators::new(mir);
         let mut promoter = Promoter {
             source: mir,
             promoted: Mir {
@@ -380,6 +762,7 @@ pub fn promote_candidates<'a, 'tcx>(mir: &mut Mir<'tcx>,
                 span: span
             },
             temps: &mut temps,
+            dominators: &dominators,
             keep_original: false
         };
         assert_eq!(promoter.new_block(), START_BLOCK);
@@ -409,4 +792,23 @@ pub fn promote_candidates<'a, 'tcx>(mir: &mut Mir<'tcx>,
             _ => {}
         }
     }
+}
+
+/// Like `promote_candidates`, but for the diagnostic mode started by
+/// `collect_temps_with_diagnostics`: `failures` is the side-channel that
+/// call produced, carried through so the caller can hand it, together
+/// with any reasons the const-qualification pass appended for this MIR's
+/// other candidates, to the borrow checker's diagnostics. Promotion of
+/// the accepted `candidates` themselves proceeds exactly as in
+/// `promote_candidates`; this wrapper exists only to keep the
+/// `Vec<(Span, PromotionFailure)>` attached to the right call instead of
+/// being silently dropped on the floor.
+pub fn promote_candidates_with_diagnostics<'a, 'tcx>(mir: &mut Mir<'tcx>,
+                                                      tcx: TyCtxt<'a, 'tcx, 'tcx>,
+                                                      temps: Vec<TempState>,
+                                                      candidates: Vec<Candidate>,
+                                                      failures: Vec<(Span, PromotionFailure)>)
+                                                      -> Vec<(Span, PromotionFailure)> {
+    promote_candidates(mir, tcx, temps, candidates);
+    failures
 }
\ No newline at end of file
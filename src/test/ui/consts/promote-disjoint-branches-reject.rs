@@ -0,0 +1,25 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Companion to promote-disjoint-branches.rs: cases that must *not* be
+// promoted, because the initializing arms don't exhaustively cover the
+// branch they come from (here, the `_ => panic!()` arm never assigns
+// anything), so a later use isn't actually dominated by an
+// initialization on every path that reaches it.
+
+fn pick(x: u8) -> &'static i32 {
+    &match x {
+        0 => 1,
+        1 => 2,
+        _ => panic!("unreachable"),
+    } //~ ERROR does not live long enough
+}
+
+fn main() {}
@@ -0,0 +1,45 @@
+// Copyright 2018 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Test that a borrow of a temporary initialized once per arm of a
+// mutually exclusive `if`/`match`, and only read after the arms merge,
+// is promoted to `'static` rather than rejected for having more than
+// one assignment.
+
+// must-compile-successfully
+
+fn pick_if(cond: bool) -> &'static i32 {
+    &if cond { 1 } else { 2 }
+}
+
+fn pick_match(x: u8) -> &'static i32 {
+    &match x {
+        0 => 10,
+        _ => 20,
+    }
+}
+
+// Same as `pick_if`, but the disjoint-branch temp is read twice within the
+// same borrowed expression, so the second `promote_temp` visit must still
+// find it `Defined` and clone it afresh, rather than seeing it already
+// marked `PromotedOut` by the first visit.
+fn pick_if_twice(cond: bool) -> &'static (i32, i32) {
+    let y = if cond { 1 } else { 2 };
+    &(y, y)
+}
+
+fn main() {
+    assert_eq!(*pick_if(true), 1);
+    assert_eq!(*pick_if(false), 2);
+    assert_eq!(*pick_match(0), 10);
+    assert_eq!(*pick_match(1), 20);
+    assert_eq!(*pick_if_twice(true), (1, 1));
+    assert_eq!(*pick_if_twice(false), (2, 2));
+}